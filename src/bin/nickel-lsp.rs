@@ -0,0 +1,14 @@
+//! Entry point for the Nickel language server.
+use lsp_server::Connection;
+use nickel_lang::server::Server;
+use std::error::Error as StdError;
+
+fn main() -> Result<(), Box<dyn StdError + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+
+    let server = Server::new(connection).map_err(|e| format!("{:?}", e))?;
+    server.run()?;
+
+    io_threads.join()?;
+    Ok(())
+}
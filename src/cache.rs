@@ -0,0 +1,996 @@
+//! Source cache and incremental query engine.
+//!
+//! This module owns every source the interpreter knows about (the main program, imports and the
+//! embedded standard library) together with the results of the various compilation stages
+//! (parsing, import resolution, typechecking, transformation and evaluation).
+//!
+//! # Demand-driven queries
+//!
+//! Instead of running a fixed `parse -> resolve_imports -> typecheck -> transform -> eval`
+//! pipeline unconditionally, [`Cache`] treats each stage as a *query* keyed by a [`FileId`]:
+//! [`Cache::parse`], [`Cache::resolve_imports`], [`Cache::typecheck`] and [`Cache::transform`].
+//! Source texts are the *inputs* of the system: every time one changes (see
+//! [`Cache::set_input`]), a global [`Revision`] counter is bumped.
+//!
+//! Each derived query remembers, in its [`Slot`], the set of other queries it read while it last
+//! ran (its `dependencies`), the revision at which it was last found to be up to date
+//! (`verified_at`) and the revision at which its output last actually changed (`changed_at`).
+//! This is the "red-green" scheme used by incremental compilers such as `rustc`'s query system or
+//! salsa:
+//!
+//! - if a query's `verified_at` is the current revision, its cached value is returned right away;
+//! - otherwise, each of its dependencies is validated recursively; if all of them turn out to have
+//!   `changed_at <= verified_at`, the query is still green: we bump `verified_at` and hand back
+//!   the old value without recomputing anything (the *early cutoff*);
+//! - if some dependency is genuinely red (it was recomputed and its output differs), the query is
+//!   recomputed, but `changed_at` is only bumped if the new output differs from the old one, so
+//!   that a change which doesn't propagate (e.g. a comment edit) doesn't force unrelated
+//!   downstream queries to redo their work either.
+//!
+//! The upshot is that editing one imported file only recomputes the queries that transitively
+//! depend on it; typechecking and transformation results for untouched files are reused even when
+//! a sibling change forces the final term to be re-evaluated.
+use crate::error::{Error, ImportError, TypecheckError};
+use crate::eval;
+use crate::identifier::Ident;
+use crate::parser::{self, lexer::Lexer};
+use crate::term::RichTerm;
+use crate::transform::{self, import_resolution};
+use crate::typecheck::{self, Context as TypeEnv};
+use crate::stdlib;
+use codespan::{FileId, Files};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// A revision number. Bumped every time an input source is changed via [`Cache::set_input`].
+pub type Revision = u64;
+
+/// The environments produced by preparing the standard library: the evaluation environment (a set
+/// of thunks bound to the stdlib's identifiers) and the typing environment used as the initial
+/// context when typechecking any other file.
+#[derive(Clone)]
+pub struct Envs {
+    pub eval_env: eval::Environment,
+    pub type_env: TypeEnv,
+}
+
+/// The distinct compilation stages that are tracked as incremental queries.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum QueryKind {
+    /// Not a derived query per se: the source text itself, as set by [`Cache::add_file`],
+    /// [`Cache::add_source`] or [`Cache::set_input`]. Every other query is, transitively, a
+    /// dependency of a query reading the corresponding source.
+    Source,
+    Parse,
+    ResolveImports,
+    Typecheck,
+    Transform,
+}
+
+/// Identifies a single query: a stage applied to a specific file.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct QueryId(QueryKind, FileId);
+
+/// The memoized output of a query, kept alongside its [`Slot`] bookkeeping.
+#[derive(Clone, PartialEq)]
+enum QueryValue {
+    Source(String),
+    Term(RichTerm),
+    /// `Typecheck` has no interesting payload beyond "it type-checked".
+    Unit,
+}
+
+/// Bookkeeping attached to a single memoized query.
+struct Slot {
+    value: QueryValue,
+    /// The dependencies read the last time this query actually ran.
+    dependencies: Vec<QueryId>,
+    /// The revision at which this query was last found to be up to date (whether recomputed or
+    /// validated via early cutoff).
+    verified_at: Revision,
+    /// The revision at which this query's output last changed.
+    changed_at: Revision,
+}
+
+/// A Nickel program cache.
+///
+/// Stores the original source code of the program and its imports, the embedded standard
+/// library, and memoizes the result of each compilation stage behind a demand-driven query
+/// engine (see the [module documentation](self)).
+pub struct Cache {
+    /// The file database, shared with error reporting.
+    files: Files<String>,
+    /// The name or path each `FileId` was registered under, for `import` resolution and error
+    /// messages.
+    file_paths: HashMap<FileId, OsString>,
+    /// `FileId`s of already-registered sources, to avoid loading the same file twice.
+    file_ids: HashMap<OsString, FileId>,
+    /// `FileId`s of the embedded standard library modules, once loaded.
+    stdlib_ids: Option<HashMap<String, FileId>>,
+    /// The current global revision. Bumped by [`Cache::set_input`].
+    revision: Revision,
+    /// Memoized query results.
+    slots: RefCell<HashMap<QueryId, Slot>>,
+    /// A stack of "currently executing query" dependency sets, used to record which queries a
+    /// running query reads. The top of the stack belongs to the innermost query currently being
+    /// computed.
+    active_deps: RefCell<Vec<Vec<QueryId>>>,
+    /// Counter used to name temporary, non-file-backed sources created by [`Cache::add_tmp`].
+    tmp_counter: usize,
+    /// If true, stdlib loading is skipped altogether (used by unit tests that don't need it).
+    pub skip_stdlib: bool,
+    /// An optional on-disk cache of already parsed and transformed terms, consulted when loading
+    /// the standard library (see [`PersistentCache`]).
+    persistent: Option<PersistentCache>,
+    /// Governs whether resolving a remote `import` is allowed to perform network I/O.
+    fetch_policy: FetchPolicy,
+    /// Maps a remote import's URL to the content hash it was last resolved to, so that
+    /// evaluation is reproducible across machines and over time. Loaded from, and written back
+    /// to, `lockfile_path` (e.g. `nickel.lock`) when set.
+    lockfile: HashMap<String, String>,
+    lockfile_path: Option<PathBuf>,
+}
+
+/// Wraps the result of a cache query, distinguishing a "real" error from the source simply not
+/// having been parsed yet. Most callers only care about the former and use
+/// [`CacheError::unwrap_error`] to assert the latter cannot happen.
+pub enum CacheError<E> {
+    Error(E),
+    NotParsed,
+}
+
+impl<E> CacheError<E> {
+    /// Turn a `CacheError` into its underlying error, panicking if the source simply hadn't been
+    /// parsed. Used at call sites that just parsed (or otherwise guaranteed the presence of) the
+    /// relevant file, where `NotParsed` would be an internal bug.
+    pub fn unwrap_error(self, msg: &str) -> E {
+        match self {
+            CacheError::Error(e) => e,
+            CacheError::NotParsed => panic!("{}", msg),
+        }
+    }
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Cache {
+            files: Files::new(),
+            file_paths: HashMap::new(),
+            file_ids: HashMap::new(),
+            stdlib_ids: None,
+            revision: 0,
+            slots: RefCell::new(HashMap::new()),
+            active_deps: RefCell::new(Vec::new()),
+            tmp_counter: 0,
+            skip_stdlib: false,
+            persistent: None,
+            fetch_policy: FetchPolicy::Online,
+            lockfile: HashMap::new(),
+            lockfile_path: None,
+        }
+    }
+
+    /// Enable the on-disk persistent cache, storing entries under `root` (typically
+    /// `.nickel/cache/`). Subsequent stdlib loads will look up and write through this cache.
+    pub fn with_persistent_cache(mut self, root: impl Into<PathBuf>) -> Self {
+        self.persistent = Some(PersistentCache::new(root));
+        self
+    }
+
+    /// Set the policy governing network access for remote (`https://`/`git+`) imports.
+    pub fn with_fetch_policy(mut self, fetch_policy: FetchPolicy) -> Self {
+        self.fetch_policy = fetch_policy;
+        self
+    }
+
+    /// Load (if present) and subsequently maintain a lockfile of resolved remote import hashes at
+    /// `path`.
+    pub fn with_lockfile(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        if let Ok(contents) = fs::read_to_string(&path) {
+            self.lockfile = parse_lockfile(&contents);
+        }
+        self.lockfile_path = Some(path);
+        self
+    }
+
+    /// Load a file from the filesystem and register it as an input.
+    pub fn add_file(&mut self, path: impl Into<OsString>) -> io::Result<FileId> {
+        let path = path.into();
+        let mut buffer = String::new();
+        fs::File::open(&path)?.read_to_string(&mut buffer)?;
+        Ok(self.add_input(path, buffer))
+    }
+
+    /// Register a generic readable source (e.g. stdin) as an input.
+    pub fn add_source<T>(&mut self, name: impl Into<OsString>, mut source: T) -> io::Result<FileId>
+    where
+        T: Read,
+    {
+        let mut buffer = String::new();
+        source.read_to_string(&mut buffer)?;
+        Ok(self.add_input(name.into(), buffer))
+    }
+
+    /// Register a source that isn't backed by a real file, such as the term built by
+    /// [`crate::program::query`] to access a subpath of the main term.
+    pub fn add_tmp(&mut self, name: &str, source: String) -> FileId {
+        self.tmp_counter += 1;
+        let name = OsString::from(format!("{}-{}", name, self.tmp_counter));
+        self.add_input(name, source)
+    }
+
+    fn add_input(&mut self, name: OsString, source: String) -> FileId {
+        let id = self.files.add(name.clone(), source.clone());
+        self.file_paths.insert(id, name.clone());
+        self.file_ids.insert(name, id);
+        self.write_source(id, source);
+        id
+    }
+
+    /// Rewrite the source of an already-registered file and bump the global revision, making
+    /// every query that transitively reads it (and only those) eligible for recomputation on
+    /// next demand.
+    ///
+    /// This is the entry point editor integrations (and the REPL) use to feed in buffer edits
+    /// without tearing down and rebuilding the whole cache.
+    pub fn set_input(&mut self, file_id: FileId, new_source: String) {
+        self.revision += 1;
+        self.write_source(file_id, new_source);
+    }
+
+    fn write_source(&mut self, file_id: FileId, source: String) {
+        let id = QueryId(QueryKind::Source, file_id);
+        let value = QueryValue::Source(source);
+        let changed = self.slots.borrow().get(&id).map_or(true, |slot| slot.value != value);
+        let changed_at = if changed {
+            self.revision
+        } else {
+            self.slots.borrow().get(&id).unwrap().changed_at
+        };
+        self.slots.borrow_mut().insert(
+            id,
+            Slot {
+                value,
+                dependencies: Vec::new(),
+                verified_at: self.revision,
+                changed_at,
+            },
+        );
+    }
+
+    /// Record that the query currently being computed (if any) read `dep`.
+    fn record_dependency(&self, dep: QueryId) {
+        if let Some(frame) = self.active_deps.borrow_mut().last_mut() {
+            frame.push(dep);
+        }
+    }
+
+    /// Read the term currently memoized for exactly `id`, without going through `demand` (and so
+    /// without itself recording a dependency or triggering recomputation). Returns `None` both
+    /// when `id` hasn't been computed yet and when its `Slot` holds a non-`Term` value (e.g.
+    /// `Typecheck`'s `QueryValue::Unit`).
+    ///
+    /// Callers that need "whatever term a specific stage produced" must go through this rather
+    /// than a single per-file mirror: a mirror overwritten by every stage can't tell a stage's own
+    /// output apart from a different stage's output for the same file, and is never refreshed on
+    /// the early-cutoff path, both of which let a stale term leak back into a later stage.
+    fn term_of(&self, id: QueryId) -> Option<RichTerm> {
+        match &self.slots.borrow().get(&id)?.value {
+            QueryValue::Term(t) => Some(t.clone()),
+            _ => None,
+        }
+    }
+
+    /// Directly install a term as the memoized output of `id`, as if it had just been computed
+    /// with no unresolved dependencies. Used where a term is obtained by a means other than
+    /// `demand` (e.g. a [`PersistentCache`] hit), but still needs to be visible to later queries
+    /// that read this slot.
+    fn insert_term_slot(&mut self, id: QueryId, term: RichTerm, dependencies: Vec<QueryId>) {
+        self.slots.borrow_mut().insert(
+            id,
+            Slot {
+                value: QueryValue::Term(term),
+                dependencies,
+                verified_at: self.revision,
+                changed_at: self.revision,
+            },
+        );
+    }
+
+    /// Check whether a query is up to date at the current revision, recursively validating its
+    /// dependencies and applying early cutoff. Leaf `Source` queries are always considered
+    /// up to date: their `changed_at` is updated eagerly by `write_source`.
+    fn is_up_to_date(&self, id: QueryId) -> bool {
+        if id.0 == QueryKind::Source {
+            return true;
+        }
+
+        let (verified_at, dependencies) = match self.slots.borrow().get(&id) {
+            Some(slot) if slot.verified_at == self.revision => return true,
+            Some(slot) => (slot.verified_at, slot.dependencies.clone()),
+            None => return false,
+        };
+
+        for dep in &dependencies {
+            if !self.is_up_to_date(*dep) {
+                return false;
+            }
+            let dep_changed_at = self.slots.borrow().get(dep).unwrap().changed_at;
+            if dep_changed_at > verified_at {
+                return false;
+            }
+        }
+
+        self.slots.borrow_mut().get_mut(&id).unwrap().verified_at = self.revision;
+        true
+    }
+
+    /// Demand the value of a query: serve it from the cache when it's still up to date, otherwise
+    /// recompute it with `compute`, recording the dependencies read along the way and only
+    /// bumping `changed_at` if the freshly computed value differs from what was cached before.
+    fn demand<F>(&mut self, id: QueryId, compute: F) -> Result<QueryValue, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<QueryValue, Error>,
+    {
+        self.record_dependency(id);
+
+        if self.is_up_to_date(id) {
+            return Ok(self.slots.borrow().get(&id).unwrap().value.clone());
+        }
+
+        self.active_deps.borrow_mut().push(Vec::new());
+        let result = compute(self);
+        let dependencies = self.active_deps.borrow_mut().pop().unwrap();
+
+        let value = result?;
+        let changed_at = match self.slots.borrow().get(&id) {
+            Some(slot) if slot.value == value => slot.changed_at,
+            _ => self.revision,
+        };
+
+        self.slots.borrow_mut().insert(
+            id,
+            Slot {
+                value: value.clone(),
+                dependencies,
+                verified_at: self.revision,
+                changed_at,
+            },
+        );
+
+        Ok(value)
+    }
+
+    fn source_of(&mut self, file_id: FileId) -> String {
+        let id = QueryId(QueryKind::Source, file_id);
+        self.record_dependency(id);
+        match &self.slots.borrow().get(&id).expect("source must be registered before being read").value {
+            QueryValue::Source(s) => s.clone(),
+            _ => unreachable!("a Source slot always holds a QueryValue::Source"),
+        }
+    }
+
+    /// Parse a source, memoized through the query engine. Re-parsing only happens if the source
+    /// text changed since the last successful parse.
+    pub fn parse(&mut self, file_id: FileId) -> Result<(), Error> {
+        self.demand(QueryId(QueryKind::Parse, file_id), |cache| {
+            let source = cache.source_of(file_id);
+            let term = parser::grammar::TermParser::new()
+                .parse_term(file_id, Lexer::new(&source))?;
+            Ok(QueryValue::Term(term))
+        })?;
+        Ok(())
+    }
+
+    /// Parse a source without going through the query engine or storing the result, bypassing the
+    /// cache entirely. Used by tooling (e.g. `nickel pprint-ast`) that wants a one-off parse of
+    /// the current text, transformed or not, without perturbing cached queries.
+    pub fn parse_nocache(&mut self, file_id: FileId) -> Result<(RichTerm, ()), Error> {
+        let source = self.source_of(file_id);
+        let term = parser::grammar::TermParser::new().parse_term(file_id, Lexer::new(&source))?;
+        Ok((term, ()))
+    }
+
+    /// Resolve and recursively load the imports of a parsed term, replacing each `import` node
+    /// with a reference to the (itself cached) imported file.
+    pub fn resolve_imports(&mut self, file_id: FileId) -> Result<(), CacheError<Error>> {
+        self.parse(file_id).map_err(CacheError::Error)?;
+        self.demand(QueryId(QueryKind::ResolveImports, file_id), |cache| {
+            // This stage reads the `Parse` query's output: record that dependency explicitly, as
+            // `demand` only auto-records a dependency on the query being demanded, not on queries
+            // read from inside its compute closure. Read the term from `Parse`'s own slot
+            // (`term_of`), not a cross-stage mirror, so a later stage can never see this stage's
+            // input confused with its own (or another file's) output.
+            let parse_id = QueryId(QueryKind::Parse, file_id);
+            cache.record_dependency(parse_id);
+            let term = cache.term_of(parse_id).expect("parse query guarantees a cached term");
+            let resolved = import_resolution::resolve_imports(term, cache)?;
+            Ok(QueryValue::Term(resolved))
+        })
+        .map(|_| ())
+        .map_err(CacheError::Error)
+    }
+
+    /// Typecheck a file against the given initial typing environment.
+    pub fn typecheck(
+        &mut self,
+        file_id: FileId,
+        type_env: &TypeEnv,
+    ) -> Result<(), CacheError<Error>> {
+        self.resolve_imports(file_id)?;
+        self.demand(QueryId(QueryKind::Typecheck, file_id), |cache| {
+            // See the matching comment in `resolve_imports`: record the dependency on the stage
+            // whose output we're about to read, and read it from that stage's own slot.
+            let resolve_imports_id = QueryId(QueryKind::ResolveImports, file_id);
+            cache.record_dependency(resolve_imports_id);
+            let term = cache
+                .term_of(resolve_imports_id)
+                .expect("resolve_imports query guarantees a cached term");
+            typecheck::type_check(&term, type_env.clone(), cache)
+                .map_err(TypecheckError::from)
+                .map_err(Error::TypecheckError)?;
+            Ok(QueryValue::Unit)
+        })
+        .map(|_| ())
+        .map_err(CacheError::Error)
+    }
+
+    /// Apply program transformations (e.g. contract generation, let-binding destructuring) to a
+    /// file that has already been typechecked.
+    pub fn transform(&mut self, file_id: FileId) -> Result<(), Error> {
+        self.demand(QueryId(QueryKind::Transform, file_id), |cache| {
+            // The term itself doesn't change across typechecking (only whether it type-checks is
+            // new information), so the term we transform is `ResolveImports`'s output; but we
+            // still depend on `Typecheck` too so that e.g. an annotation edit that doesn't touch
+            // the resolved term's shape still invalidates a previously transformed result.
+            let typecheck_id = QueryId(QueryKind::Typecheck, file_id);
+            let resolve_imports_id = QueryId(QueryKind::ResolveImports, file_id);
+            cache.record_dependency(typecheck_id);
+            cache.record_dependency(resolve_imports_id);
+            let term = cache
+                .term_of(resolve_imports_id)
+                .expect("resolve_imports query guarantees a cached term");
+            // Transformations are assumed infallible past typechecking, mirroring
+            // `Program::pprint_ast`'s use of the same function.
+            let transformed = transform::transform(term, None)
+                .expect("Cache::transform(): transform of a typechecked term failed");
+            Ok(QueryValue::Term(transformed))
+        })?;
+        Ok(())
+    }
+
+    /// Run the full `parse -> resolve_imports -> typecheck -> transform` pipeline for `file_id`,
+    /// reusing every stage whose dependencies haven't changed since the last time it was demanded.
+    pub fn prepare(&mut self, file_id: FileId, type_env: &TypeEnv) -> Result<(), Error> {
+        self.typecheck(file_id, type_env)
+            .map_err(|cache_err| cache_err.unwrap_error("Cache::prepare(): expected source to be parsed"))?;
+        self.transform(file_id)
+    }
+
+    /// Parse and typecheck the embedded standard library, and build its evaluation and typing
+    /// environments. Memoized: repeated calls across the lifetime of one `Cache` are free.
+    pub fn prepare_stdlib(&mut self) -> Result<Envs, Error> {
+        if self.skip_stdlib {
+            return Ok(Envs {
+                eval_env: eval::Environment::new(),
+                type_env: TypeEnv::new(),
+            });
+        }
+
+        self.load_stdlib()?;
+        let mut type_env = self
+            .mk_type_env()
+            .expect("Cache::prepare_stdlib(): stdlib has been loaded but was not found in cache");
+
+        let stdlib_ids = self.stdlib_ids.clone().unwrap();
+        let mut eval_env = eval::Environment::new();
+        for (name, file_id) in stdlib_ids {
+            // `content_hash` walks the dependency edges recorded on the `ResolveImports` query
+            // (which, transitively, cover every file it imports); those edges only exist once
+            // `resolve_imports` has actually run, so we pay for that cheap step unconditionally
+            // before consulting the persistent cache, even on what turns out to be a hit.
+            self.resolve_imports(file_id).map_err(|cache_err| {
+                cache_err.unwrap_error("Cache::prepare_stdlib(): expected stdlib module to be parsed")
+            })?;
+
+            let persistent = self.persistent.clone();
+            let term = match persistent.as_ref().and_then(|p| p.load(self, file_id)) {
+                Some((term, _module_type_env)) => {
+                    // This bypasses `typecheck`/`transform` entirely, so install the loaded term
+                    // as `Transform`'s own output directly (see `insert_term_slot`) rather than
+                    // through a side-channel mirror, keeping `Cache::get` and future `demand`
+                    // calls for this file consistent with the rest of the query engine.
+                    self.insert_term_slot(
+                        QueryId(QueryKind::Transform, file_id),
+                        term.clone(),
+                        vec![QueryId(QueryKind::ResolveImports, file_id)],
+                    );
+                    // `mk_type_env` seeded `type_env` with this module's bare parsed term, before
+                    // it was resolved or transformed. `_module_type_env` carries exactly the same
+                    // `name -> term` binding we'd otherwise reconstruct (see the miss branch
+                    // below), so fold it in here too: any stdlib module typechecked later in this
+                    // loop must see this module's actual final contents, not the parse-stage stub.
+                    type_env.insert(Ident::from(&name), term.clone());
+                    term
+                }
+                None => {
+                    self.typecheck(file_id, &type_env).map_err(|cache_err| {
+                        cache_err.unwrap_error(
+                            "Cache::prepare_stdlib(): expected stdlib module to be parsed",
+                        )
+                    })?;
+                    self.transform(file_id)?;
+                    let term = self.get(file_id).unwrap();
+                    if let Some(p) = &persistent {
+                        let mut module_type_env = TypeEnv::new();
+                        module_type_env.insert(Ident::from(&name), term.clone());
+                        // Best-effort: a write failure just means the next run re-typechecks.
+                        let _ = p.store(self, file_id, &term, &module_type_env);
+                    }
+                    type_env.insert(Ident::from(&name), term.clone());
+                    term
+                }
+            };
+            eval::env_add(&mut eval_env, Ident::from(name), term, eval::Environment::new());
+        }
+
+        Ok(Envs { eval_env, type_env })
+    }
+
+    /// Load (parse) the embedded standard library modules, registering each as a file.
+    pub fn load_stdlib(&mut self) -> Result<(), Error> {
+        if self.stdlib_ids.is_some() {
+            return Ok(());
+        }
+
+        let mut ids = HashMap::new();
+        for (name, source) in stdlib::modules() {
+            let file_id =
+                self.add_input(OsString::from(format!("<stdlib/{}.ncl>", name)), source.to_string());
+            self.parse(file_id)?;
+            ids.insert(name.to_string(), file_id);
+        }
+        self.stdlib_ids = Some(ids);
+        Ok(())
+    }
+
+    /// Build the typing environment out of the already-loaded standard library.
+    pub fn mk_type_env(&self) -> Option<TypeEnv> {
+        let stdlib_ids = self.stdlib_ids.as_ref()?;
+        let mut type_env = TypeEnv::new();
+        for (name, file_id) in stdlib_ids {
+            let term = self.get(*file_id)?;
+            type_env.insert(Ident::from(name), term);
+        }
+        Some(type_env)
+    }
+
+    /// Retrieve the most advanced term produced for `file_id`, preferring the furthest-along
+    /// stage that has actually completed (transformed, otherwise import-resolved, otherwise
+    /// merely parsed). Each candidate is read straight from that stage's own `Slot` (via
+    /// [`Cache::term_of`]), so this can never confuse one stage's output for another's.
+    pub fn get(&self, file_id: FileId) -> Option<RichTerm> {
+        self.term_of(QueryId(QueryKind::Transform, file_id))
+            .or_else(|| self.term_of(QueryId(QueryKind::ResolveImports, file_id)))
+            .or_else(|| self.term_of(QueryId(QueryKind::Parse, file_id)))
+    }
+
+    pub fn get_owned(&self, file_id: FileId) -> Option<RichTerm> {
+        self.get(file_id)
+    }
+
+    /// Look up the `FileId` a given name (path or stdlib module name) was registered under.
+    pub fn id_of(&self, name: &str) -> Option<FileId> {
+        self.file_ids.get(&OsString::from(name)).copied()
+    }
+
+    /// The inverse of [`Cache::id_of`]: the name or path `file_id` was registered under (a
+    /// filesystem path for a local file or `import`, or the URL itself for a remote import).
+    pub fn path_of(&self, file_id: FileId) -> Option<&OsString> {
+        self.file_paths.get(&file_id)
+    }
+
+    pub fn files_mut(&mut self) -> &mut Files<String> {
+        &mut self.files
+    }
+
+    /// Resolve an `import` target relative to the importing file, loading and registering it as a
+    /// new input if it hasn't been seen yet.
+    pub fn resolve_import(
+        &mut self,
+        path: impl AsRef<Path>,
+        parent: FileId,
+    ) -> Result<FileId, ImportError> {
+        let path = path.as_ref();
+
+        if let Some(url) = path.to_str().and_then(RemoteScheme::detect) {
+            return self.resolve_remote_import(path.to_str().unwrap(), url);
+        }
+
+        // Look the import up under the same parent-relative path `add_file` registers it with,
+        // so that an already-loaded import (and any `set_input` edit to it) is reused.
+        let parent_dir = self
+            .file_paths
+            .get(&parent)
+            .map(PathBuf::from)
+            .and_then(|p| p.parent().map(PathBuf::from))
+            .unwrap_or_default();
+        let full_path = parent_dir.join(path);
+        let name = OsString::from(&full_path);
+
+        let file_id = if let Some(id) = self.file_ids.get(&name) {
+            *id
+        } else {
+            self.add_file(full_path.clone()).map_err(|io_err| {
+                ImportError::IOError(
+                    full_path.to_string_lossy().into_owned(),
+                    io_err.to_string(),
+                    crate::position::TermPos::None,
+                )
+            })?
+        };
+
+        // Demanding `Parse` here, regardless of which branch above produced `file_id`, is what
+        // records the importer -> import dependency edge: `demand` records a dependency on the
+        // query it's asked for against whichever frame is currently active, and we're called from
+        // inside `resolve_imports`'s own compute closure, so that frame is the importer's
+        // `ResolveImports` query. Without this, `set_input` on an imported file would bump the
+        // revision but leave the importer's queries looking up to date.
+        self.parse(file_id)
+            .map_err(|e| ImportError::IOError(name.to_string_lossy().into_owned(), format!("{:?}", e), crate::position::TermPos::None))?;
+
+        Ok(file_id)
+    }
+
+    /// Resolve a `https://`/`http://`/`git+` import: fetch it (subject to [`FetchPolicy`]),
+    /// store it under its content hash in the local fetch cache, pin that hash in the lockfile,
+    /// and register the result as a regular `FileId`.
+    fn resolve_remote_import(&mut self, url: &str, scheme: RemoteScheme) -> Result<FileId, ImportError> {
+        let io_err = |msg: String| ImportError::IOError(url.to_string(), msg, crate::position::TermPos::None);
+
+        let file_id = if let Some(id) = self.file_ids.get(&OsString::from(url)) {
+            *id
+        } else {
+            let pinned = self.lockfile.get(url).cloned();
+
+            let (source, hash) = match (self.fetch_policy, &pinned) {
+                (FetchPolicy::Offline, None) => {
+                    return Err(io_err("remote imports are disabled (offline fetch policy)".to_string()))
+                }
+                (FetchPolicy::Offline, Some(hash)) | (FetchPolicy::CachedOnly, Some(hash)) => {
+                    let source = read_fetch_cache(hash)
+                        .ok_or_else(|| io_err(format!("no local copy of pinned hash {}", hash)))?;
+                    (source, hash.clone())
+                }
+                (FetchPolicy::CachedOnly, None) => {
+                    return Err(io_err(
+                        "no lockfile entry for this import and fetching is disabled (cached-only fetch policy)"
+                            .to_string(),
+                    ))
+                }
+                (FetchPolicy::Online, _) => {
+                    // Prefer what's already pinned and on disk over hitting the network again.
+                    match pinned.as_ref().and_then(|hash| read_fetch_cache(hash).map(|s| (hash.clone(), s))) {
+                        Some((hash, source)) => (source, hash),
+                        None => {
+                            let source = match &scheme {
+                                RemoteScheme::Http => fetch_http(url).map_err(io_err)?,
+                                RemoteScheme::Git { repo, rev } => fetch_git(repo, rev).map_err(io_err)?,
+                            };
+                            let hash = sha256_hex(source.as_bytes());
+
+                            if let Some(pinned) = &pinned {
+                                if pinned != &hash {
+                                    return Err(io_err(format!(
+                                        "hash mismatch: lockfile pins {} but the remote now resolves to {}",
+                                        pinned, hash
+                                    )));
+                                }
+                            }
+
+                            write_fetch_cache(&hash, &source).map_err(|e| io_err(e.to_string()))?;
+                            (source, hash)
+                        }
+                    }
+                }
+            };
+
+            self.register_fetched(url, hash, source)
+        };
+
+        // See the matching comment in `resolve_import`: demanding `Parse` here records the
+        // importer -> import dependency edge against the currently active `ResolveImports` frame,
+        // regardless of which branch above produced `file_id`.
+        self.parse(file_id).map_err(|e| io_err(format!("{:?}", e)))?;
+
+        Ok(file_id)
+    }
+
+    fn register_fetched(&mut self, url: &str, hash: String, source: String) -> FileId {
+        self.lockfile.insert(url.to_string(), hash);
+        self.save_lockfile();
+        self.add_input(OsString::from(url), source)
+    }
+
+    fn save_lockfile(&self) {
+        if let Some(path) = &self.lockfile_path {
+            // Best-effort: an unwritable lockfile shouldn't make evaluation fail, it just means
+            // the pin won't be remembered for next time.
+            let _ = fs::write(path, render_lockfile(&self.lockfile));
+        }
+    }
+
+    /// A hash of `file_id`'s source text together with, transitively, the source text of every
+    /// file it imports (as recorded by the query engine's own dependency tracking) and a schema
+    /// version tag. Two processes computing the same hash for a file are guaranteed to produce
+    /// the same parsed and typechecked term, which is what makes it safe to key
+    /// [`PersistentCache`] entries on. Built on [`sha2::Sha256`] (via [`sha256_hex`]) rather than
+    /// [`std::collections::hash_map::DefaultHasher`], whose output std explicitly does not
+    /// guarantee stable across Rust versions or even separate runs of the same binary -- which
+    /// would otherwise silently invalidate every persisted entry on a toolchain bump.
+    ///
+    /// This seeds the traversal at the `ResolveImports` query rather than `Parse`: `Parse`'s only
+    /// recorded dependency is the file's own source, since import edges are discovered (and
+    /// recorded) while resolving imports. Callers must therefore have already run
+    /// [`Cache::resolve_imports`] on `file_id` before calling this, or the hash silently degrades
+    /// to covering the file's own source only, missing changes to its imports entirely.
+    fn content_hash(&self, file_id: FileId) -> String {
+        let mut seen = std::collections::HashSet::new();
+        let mut stack = vec![QueryId(QueryKind::ResolveImports, file_id)];
+        let mut sources = Vec::new();
+
+        while let Some(id) = stack.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            if let Some(slot) = self.slots.borrow().get(&id) {
+                stack.extend(slot.dependencies.iter().copied());
+            }
+            if id.0 == QueryKind::Source {
+                if let Some(slot) = self.slots.borrow().get(&id) {
+                    if let QueryValue::Source(s) = &slot.value {
+                        let name = self.file_paths.get(&id.1).cloned().unwrap_or_default();
+                        sources.push((name, s.clone()));
+                    }
+                }
+            }
+        }
+        sources.sort();
+
+        // Length-prefix every field so that e.g. ("ab", "c") and ("a", "bc") can never collide.
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&PersistentCache::SCHEMA_VERSION.to_le_bytes());
+        for (name, source) in &sources {
+            let name = name.to_string_lossy();
+            buffer.extend_from_slice(&(name.len() as u64).to_le_bytes());
+            buffer.extend_from_slice(name.as_bytes());
+            buffer.extend_from_slice(&(source.len() as u64).to_le_bytes());
+            buffer.extend_from_slice(source.as_bytes());
+        }
+
+        sha256_hex(&buffer)
+    }
+}
+
+/// An on-disk cache of already parsed and transformed [`RichTerm`]s, keyed by the content hash of
+/// their source plus their transitive imports (see [`Cache::content_hash`]).
+///
+/// This is what lets `nickel eval`/`nickel typecheck` skip re-parsing and re-typechecking the
+/// (large) embedded standard library on every process start: as long as the stdlib sources and
+/// the cache's schema version haven't changed, [`PersistentCache::load`] deserializes the
+/// previous result straight off disk.
+/// Entries are serialized with `bincode`, which requires [`RichTerm`]/[`crate::term::Term`] and
+/// the type environment types to derive `serde::Serialize`/`serde::Deserialize`; those derives
+/// live alongside the type definitions in `term.rs`/`typecheck.rs`.
+#[derive(Clone)]
+pub struct PersistentCache {
+    root: PathBuf,
+}
+
+impl PersistentCache {
+    /// Bumped whenever the serialized representation of a cache entry changes, so that stale
+    /// entries from a previous version of Nickel are simply treated as misses rather than being
+    /// (potentially unsafely) deserialized.
+    const SCHEMA_VERSION: u32 = 1;
+
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        PersistentCache { root: root.into() }
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.root.join(format!("{}.bin", hash))
+    }
+
+    /// Look up a previously persisted term and type environment for `file_id`, if its content
+    /// hash matches an entry on disk.
+    pub fn load(&self, cache: &Cache, file_id: FileId) -> Option<(RichTerm, TypeEnv)> {
+        let path = self.entry_path(&cache.content_hash(file_id));
+        let bytes = fs::read(path).ok()?;
+        let entry: PersistedEntry = bincode::deserialize(&bytes).ok()?;
+        Some((entry.term, entry.type_env))
+    }
+
+    /// Persist the (parsed and transformed) term for `file_id`, along with the type environment
+    /// it contributes, under its content hash.
+    pub fn store(
+        &self,
+        cache: &Cache,
+        file_id: FileId,
+        term: &RichTerm,
+        type_env: &TypeEnv,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.root)?;
+        let entry = PersistedEntry { term: term.clone(), type_env: type_env.clone() };
+        let bytes = bincode::serialize(&entry)
+            .expect("RichTerm and TypeEnv derive Serialize, so serialization cannot fail");
+        fs::write(self.entry_path(&cache.content_hash(file_id)), bytes)
+    }
+}
+
+/// The on-disk representation of one [`PersistentCache`] entry.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PersistedEntry {
+    term: RichTerm,
+    type_env: TypeEnv,
+}
+
+/// Governs whether resolving a remote `import` is allowed to perform network I/O.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FetchPolicy {
+    /// Never touch the network; a remote import that isn't already on disk is a hard error.
+    Offline,
+    /// Only serve remote imports that are both pinned in the lockfile and already present in the
+    /// local fetch cache; like `Offline`, but distinguishes "I haven't fetched this yet" from "I
+    /// never want to fetch anything" in error messages.
+    CachedOnly,
+    /// Fetch over the network as needed, pinning newly resolved hashes in the lockfile.
+    Online,
+}
+
+/// The two remote import schemes `Cache::resolve_import` understands: plain HTTP(S) URLs, and
+/// `git+<repo-url>#<rev>` references.
+enum RemoteScheme {
+    Http,
+    Git { repo: String, rev: String },
+}
+
+impl RemoteScheme {
+    fn detect(path: &str) -> Option<RemoteScheme> {
+        if let Some(rest) = path.strip_prefix("git+") {
+            let (repo, rev) = rest.split_once('#')?;
+            Some(RemoteScheme::Git { repo: repo.to_string(), rev: rev.to_string() })
+        } else if path.starts_with("https://") || path.starts_with("http://") {
+            Some(RemoteScheme::Http)
+        } else {
+            None
+        }
+    }
+}
+
+/// The local, content-addressed store of already-fetched remote sources, mirroring what package
+/// managers do for downloaded sources: `~/.cache/nickel/registry/<sha256>`.
+fn fetch_cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("nickel")
+        .join("registry")
+}
+
+fn read_fetch_cache(hash: &str) -> Option<String> {
+    fs::read_to_string(fetch_cache_dir().join(hash)).ok()
+}
+
+fn write_fetch_cache(hash: &str, source: &str) -> io::Result<()> {
+    let dir = fetch_cache_dir();
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(hash), source)
+}
+
+fn fetch_http(url: &str) -> Result<String, String> {
+    ureq::get(url)
+        .call()
+        .map_err(|e| format!("fetching {}: {}", url, e))?
+        .into_string()
+        .map_err(|e| format!("reading response body from {}: {}", url, e))
+}
+
+/// Shallow-clone `repo` at `rev` into a temporary directory and read back the single file the
+/// import is expected to point at.
+//TODO: this only supports importing a whole repository that itself is a single Nickel file's
+// worth of content (e.g. via a `default.ncl` at the repo root); resolving a path *within* the
+// repository (`git+https://...//sub/dir/file.ncl#rev`) is left for a follow-up.
+fn fetch_git(repo: &str, rev: &str) -> Result<String, String> {
+    let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+
+    let clone = std::process::Command::new("git")
+        .args(["clone", "--quiet", "--depth", "1", repo, "."])
+        .current_dir(dir.path())
+        .status()
+        .map_err(|e| format!("running git clone: {}", e))?;
+    if !clone.success() {
+        return Err(format!("git clone of {} failed", repo));
+    }
+
+    let checkout = std::process::Command::new("git")
+        .args(["checkout", "--quiet", rev])
+        .current_dir(dir.path())
+        .status()
+        .map_err(|e| format!("running git checkout: {}", e))?;
+    if !checkout.success() {
+        return Err(format!("git checkout of {} at {} failed", repo, rev));
+    }
+
+    fs::read_to_string(dir.path().join("default.ncl"))
+        .map_err(|e| format!("reading default.ncl from {}#{}: {}", repo, rev, e))
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The lockfile format is intentionally simple: one `<url> <sha256>` pair per line, sorted by URL
+/// for stable diffs.
+fn parse_lockfile(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(url, hash)| (url.to_string(), hash.trim().to_string()))
+        .collect()
+}
+
+fn render_lockfile(lockfile: &HashMap<String, String>) -> String {
+    let mut entries: Vec<_> = lockfile.iter().collect();
+    entries.sort();
+    entries
+        .into_iter()
+        .map(|(url, hash)| format!("{} {}\n", url, hash))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Regression test for the importer -> import dependency edge that [`Cache::resolve_import`]
+    /// and [`Cache::resolve_remote_import`] must record: editing an imported file should make the
+    /// importer's `ResolveImports` query stale, not merely the import's own.
+    #[test]
+    fn editing_an_import_invalidates_the_importer() {
+        let dir = tempfile::tempdir().unwrap();
+        let imported_path = dir.path().join("imported.ncl");
+        fs::write(&imported_path, "1").unwrap();
+
+        let importer_path = dir.path().join("importer.ncl");
+        fs::write(&importer_path, "import \"imported.ncl\"").unwrap();
+
+        let mut cache = Cache::new();
+        cache.skip_stdlib = true;
+        let importer_id = cache.add_file(importer_path.clone()).unwrap();
+
+        cache.resolve_imports(importer_id).unwrap();
+        let imported_id = cache.id_of(imported_path.to_str().unwrap()).unwrap();
+        let resolve_imports_id = QueryId(QueryKind::ResolveImports, importer_id);
+        assert!(cache.is_up_to_date(resolve_imports_id));
+
+        cache.set_input(imported_id, "2".to_string());
+        assert!(
+            !cache.is_up_to_date(resolve_imports_id),
+            "editing the import must invalidate the importer's ResolveImports query"
+        );
+
+        cache.resolve_imports(importer_id).unwrap();
+        assert!(cache.is_up_to_date(resolve_imports_id));
+    }
+}
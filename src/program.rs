@@ -24,7 +24,7 @@ use crate::cache::*;
 use crate::error::{Error, ToDiagnostic};
 use crate::identifier::Ident;
 use crate::parser::lexer::Lexer;
-use crate::term::{RichTerm, Term};
+use crate::term::{MergePriority, RichTerm, Term};
 use crate::{eval, parser};
 use codespan::FileId;
 use codespan_reporting::term::termcolor::{ColorChoice, StandardStream};
@@ -100,6 +100,14 @@ impl Program {
         query(&mut self.cache, self.main_id, &initial_env, path)
     }
 
+    /// Same query as [`Program::query`], but returning the machine-readable [`QueryResult`]
+    /// instead of a weakly-evaluated term meant for pretty-printing. The `nickel query`
+    /// subcommand does not call it.
+    pub fn query_structured(&mut self, path: Option<String>) -> Result<QueryResult, Error> {
+        let initial_env = self.cache.prepare_stdlib()?;
+        query_structured(&mut self.cache, self.main_id, &initial_env, path)
+    }
+
     /// Load, parse, and typecheck the program and the standard library, if not already done.
     pub fn typecheck(&mut self) -> Result<(), Error> {
         self.cache.parse(self.main_id)?;
@@ -132,6 +140,15 @@ impl Program {
         doc::output_doc(&mut self.cache, self.main_id, out)
     }
 
+    /// Same as [`Program::output_doc`], but renders a single navigable HTML page instead of flat
+    /// CommonMark: a collapsible sidebar mirrors the record's field nesting, each field gets its
+    /// own anchored section, and fields whose contract is another documented record link to that
+    /// record's section.
+    #[cfg(feature = "doc")]
+    pub fn output_doc_html(&mut self, out: &mut dyn std::io::Write) -> Result<(), Error> {
+        doc::output_doc_html(&mut self.cache, self.main_id, out)
+    }
+
     #[cfg(debug_assertions)]
     pub fn set_skip_stdlib(&mut self) {
         self.cache.skip_stdlib = true;
@@ -208,6 +225,89 @@ pub fn query(
     Ok(eval::eval_meta(t, &initial_env.eval_env, cache)?.into())
 }
 
+/// Machine-readable description of a [`query`]d path, suitable for serializing to JSON for
+/// editors and other tooling that want to introspect a config's schema rather than scrape
+/// pretty-printed text.
+#[derive(serde::Serialize)]
+pub struct QueryResult {
+    /// The field's value, pretty-printed, once forced (absent if the field has no value, e.g. an
+    /// undefined record field that only carries a contract).
+    pub value: Option<String>,
+    /// The field's doc comment, if any.
+    pub doc: Option<String>,
+    /// The contracts applied to the field, pretty-printed in application order.
+    pub contracts: Vec<String>,
+    /// The field's merge priority, if it was given one explicitly.
+    pub priority: Option<String>,
+    /// The field's type, taken from its annotation if present.
+    ///
+    /// This does not fall back to an inferred type when there
+    /// is no explicit annotation: `Cache::prepare`'s typing environment only binds the top-level
+    /// stdlib modules (see `Cache::mk_type_env`), not the per-field types `typecheck::type_check`
+    /// derives while walking the program, and the latter currently has no way to hand those back
+    /// out. Reporting inferred types here needs that to be threaded out of `typecheck` first.
+    pub typ: Option<String>,
+}
+
+/// Same query as [`query`], but returning a [`QueryResult`] instead of a weakly-evaluated term
+/// meant for human display.
+pub fn query_structured(
+    cache: &mut Cache,
+    file_id: FileId,
+    initial_env: &Envs,
+    path: Option<String>,
+) -> Result<QueryResult, Error> {
+    let term = query(cache, file_id, initial_env, path)?;
+
+    Ok(match &term {
+        Term::MetaValue(meta) => QueryResult {
+            value: meta.value.as_ref().map(pretty_string),
+            doc: meta.doc.clone(),
+            contracts: meta.contracts.iter().map(|c| format!("{}", c)).collect(),
+            priority: priority_string(&meta.priority),
+            typ: meta.types.as_ref().map(|ty| format!("{}", ty)),
+        },
+        _ => QueryResult {
+            value: Some(pretty_string(&term.into())),
+            doc: None,
+            contracts: Vec::new(),
+            priority: None,
+            typ: None,
+        },
+    })
+}
+
+/// Stable, machine-readable name for a merge priority, or `None` for [`MergePriority::Normal`]
+/// (the default every field gets unless it's annotated otherwise), so that
+/// [`QueryResult::priority`] only ever reports a priority the field was explicitly given.
+fn priority_string(priority: &MergePriority) -> Option<String> {
+    if *priority == MergePriority::default() {
+        return None;
+    }
+
+    Some(
+        match priority {
+            MergePriority::Bottom => "bottom",
+            MergePriority::Normal => "normal",
+            MergePriority::Top => "top",
+        }
+        .to_string(),
+    )
+}
+
+/// Pretty-print a term the same way `Program::pprint_ast` does, but to an owned `String` instead
+/// of a writer.
+fn pretty_string(rt: &RichTerm) -> String {
+    use crate::pretty::*;
+    use pretty::BoxAllocator;
+
+    let allocator = BoxAllocator;
+    let doc: DocBuilder<_, ()> = rt.pretty(&allocator);
+    let mut buf = Vec::new();
+    doc.render(80, &mut buf).expect("rendering to an in-memory buffer cannot fail");
+    String::from_utf8(buf).expect("the pretty printer always emits valid UTF-8")
+}
+
 /// Pretty-print an error.
 ///
 /// This function is located here in `Program` because errors need a reference to `files` in order
@@ -243,9 +343,31 @@ mod doc {
     use codespan::FileId;
     use comrak::arena_tree::NodeEdge;
     use comrak::nodes::{Ast, AstNode, NodeCode, NodeHeading, NodeValue};
-    use comrak::{format_commonmark, parse_document, Arena, ComrakOptions};
+    use comrak::{format_commonmark, format_html, parse_document, Arena, ComrakOptions};
+    use std::collections::HashMap;
     use std::io::Write;
 
+    fn io_err(e: std::io::Error) -> Error {
+        Error::IOError(IOError(e.to_string()))
+    }
+
+    /// Escape the five characters that are significant to an HTML parser. Used for any
+    /// content-derived string (field names, type annotations, ...) interpolated into the output,
+    /// since none of it can be trusted not to contain `<`, `&`, etc.
+    fn html_escape(s: &str) -> String {
+        s.chars().fold(String::with_capacity(s.len()), |mut acc, c| {
+            match c {
+                '&' => acc.push_str("&amp;"),
+                '<' => acc.push_str("&lt;"),
+                '>' => acc.push_str("&gt;"),
+                '"' => acc.push_str("&quot;"),
+                '\'' => acc.push_str("&#39;"),
+                _ => acc.push(c),
+            }
+            acc
+        })
+    }
+
     /// Create a markdown file with documentation for the specified FileId.
     pub fn output_doc(
         cache: &mut Cache,
@@ -254,7 +376,7 @@ mod doc {
     ) -> Result<(), Error> {
         cache.parse(file_id)?;
         // unwrap(): at this point the term was correctly parsed and should exist in cache
-        let term = cache.get_ref(file_id).unwrap();
+        let term = cache.get(file_id).unwrap();
         let document = AstNode::from(NodeValue::Document);
 
         // Our nodes in the Markdown document are owned by this arena
@@ -263,7 +385,7 @@ mod doc {
         // The default ComrakOptions disables all extensions (essentially reducing to CommonMark)
         let options = ComrakOptions::default();
 
-        to_markdown(term, 0, &arena, &document, &options)?;
+        to_markdown(&term, 0, &arena, &document, &options)?;
         format_commonmark(&document, &options, out)
             .map_err(|e| Error::IOError(IOError(e.to_string())))?;
 
@@ -299,6 +421,259 @@ mod doc {
         Ok(())
     }
 
+    /// One entry of the field tree built while walking a record for HTML output: its dotted
+    /// path (e.g. `["foo", "bar"]` for `foo.bar`), its doc comment rendered to an HTML fragment,
+    /// the raw pieces of its signature (type annotation / default value, rendered and cross-linked
+    /// later by [`mk_signature`] once the full tree is known), and the nested fields it contains
+    /// when its value is itself a record.
+    struct HtmlField {
+        path: Vec<String>,
+        doc_html: Option<String>,
+        /// The field's type annotation or, failing that, its first contract, rendered to plain
+        /// text (not yet HTML-escaped or linked).
+        types: Option<String>,
+        has_default: bool,
+        children: Vec<HtmlField>,
+    }
+
+    impl HtmlField {
+        fn anchor(&self) -> String {
+            self.path.join(".")
+        }
+
+        fn name(&self) -> &str {
+            self.path.last().map(String::as_str).unwrap_or("")
+        }
+    }
+
+    /// Render a full, navigable HTML documentation page: a collapsible sidebar mirroring the
+    /// record's field nesting, and one anchored `<section>` per field. A field whose contract is
+    /// itself a documented record cross-links to that record's section, and deep-linking to
+    /// `#foo.bar.baz` scrolls the right field into view and expands its ancestors in the sidebar.
+    pub fn output_doc_html(
+        cache: &mut Cache,
+        file_id: FileId,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        cache.parse(file_id)?;
+        // unwrap(): at this point the term was correctly parsed and should exist in cache
+        let term = cache.get(file_id).unwrap();
+        let options = ComrakOptions::default();
+        let root = collect_fields(&term, Vec::new(), &options);
+
+        let mut registry = HashMap::new();
+        build_registry(&root, &mut registry);
+
+        writeln!(out, "<!DOCTYPE html>").map_err(io_err)?;
+        writeln!(
+            out,
+            "<html><head><meta charset=\"utf-8\"><title>Nickel documentation</title>{}</head>",
+            STYLE
+        )
+        .map_err(io_err)?;
+        writeln!(out, "<body>").map_err(io_err)?;
+        writeln!(out, "<nav id=\"sidebar\"><ul>").map_err(io_err)?;
+        write_sidebar(&root.children, out)?;
+        writeln!(out, "</ul></nav>").map_err(io_err)?;
+        writeln!(out, "<main>").map_err(io_err)?;
+        write_sections(&root.children, &registry, out)?;
+        writeln!(out, "</main>{}</body></html>", SCROLL_SCRIPT).map_err(io_err)?;
+
+        Ok(())
+    }
+
+    /// Walk a term, collecting one [`HtmlField`] per field reachable under `path`, documented or
+    /// not.
+    fn collect_fields(rt: &RichTerm, path: Vec<String>, options: &ComrakOptions) -> HtmlField {
+        match rt.term.as_ref() {
+            Term::MetaValue(meta @ MetaValue { doc, .. }) => {
+                let doc_html = doc.as_ref().map(|md| render_doc(md, options));
+                let types = meta
+                    .types
+                    .as_ref()
+                    .map(|ty| format!("{}", ty))
+                    .or_else(|| meta.contracts.first().map(|c| format!("{}", c)));
+                let has_default = meta.value.is_some();
+                let children = match meta.value.as_ref().map(|v| v.term.as_ref()) {
+                    Some(Term::Record(map, _)) | Some(Term::RecRecord(map, _, _, _)) => {
+                        let mut entries: Vec<(_, _)> = map.iter().collect();
+                        entries.sort_by_key(|(k, _)| *k);
+                        entries
+                            .into_iter()
+                            .map(|(ident, rt)| {
+                                let mut child_path = path.clone();
+                                child_path.push(ident.label.clone());
+                                collect_fields(rt, child_path, options)
+                            })
+                            .collect()
+                    }
+                    _ => Vec::new(),
+                };
+                HtmlField { path, doc_html, types, has_default, children }
+            }
+            Term::Record(map, _) | Term::RecRecord(map, _, _, _) => {
+                let mut entries: Vec<(_, _)> = map.iter().collect();
+                entries.sort_by_key(|(k, _)| *k);
+                let children = entries
+                    .into_iter()
+                    .map(|(ident, rt)| {
+                        let mut child_path = path.clone();
+                        child_path.push(ident.label.clone());
+                        collect_fields(rt, child_path, options)
+                    })
+                    .collect();
+                HtmlField { path, doc_html: None, types: None, has_default: false, children }
+            }
+            _ => HtmlField {
+                path,
+                doc_html: None,
+                types: None,
+                has_default: false,
+                children: Vec::new(),
+            },
+        }
+    }
+
+    /// Build a map from a documented record's own name (its last path segment) to its anchor, for
+    /// every record-valued field in the tree. Used by [`mk_signature`] to cross-link a field whose
+    /// type annotation or contract names another record documented in the same file.
+    ///
+    /// Matching on the bare name is a heuristic: it only catches the common pattern where a
+    /// contract is itself a documented field (e.g. `server | Server`, with `Server` documented
+    /// elsewhere in the same file), not arbitrary type expressions such as `List Server`.
+    fn build_registry(field: &HtmlField, registry: &mut HashMap<String, String>) {
+        if !field.path.is_empty() && !field.children.is_empty() {
+            registry.insert(field.name().to_string(), field.anchor());
+        }
+        for child in &field.children {
+            build_registry(child, registry);
+        }
+    }
+
+    /// Best-effort one-line signature for a field: its type annotation or, failing that, its first
+    /// contract, shown after `: ` (cross-linked to another documented record's section when its
+    /// name matches one), plus a marker when a default value
+    /// is present. The result is ready-to-embed HTML, already escaped.
+    fn mk_signature(field: &HtmlField, registry: &HashMap<String, String>) -> Option<String> {
+        if field.types.is_none() && !field.has_default {
+            return None;
+        }
+
+        let mut sig = String::new();
+        if let Some(ty) = &field.types {
+            sig.push_str(": ");
+            match registry.get(ty.trim()) {
+                Some(anchor) => sig.push_str(&format!(
+                    "<a href=\"#{}\">{}</a>",
+                    html_escape(anchor),
+                    html_escape(ty)
+                )),
+                None => sig.push_str(&html_escape(ty)),
+            }
+        }
+        if field.has_default {
+            sig.push_str(" (has default)");
+        }
+        Some(sig)
+    }
+
+    fn render_doc(md: &str, options: &ComrakOptions) -> String {
+        let arena = Arena::new();
+        let node = parse_document(&arena, md, options);
+        let mut buf = Vec::new();
+        format_html(node, options, &mut buf)
+            .expect("rendering markdown to an in-memory buffer cannot fail");
+        String::from_utf8(buf).expect("comrak always emits valid UTF-8")
+    }
+
+    /// Render the collapsible `<li>` tree mirroring the record's nesting.
+    fn write_sidebar(fields: &[HtmlField], out: &mut dyn Write) -> Result<(), Error> {
+        for field in fields {
+            // A field name (and hence the anchor derived from it) can contain a `"`, which would
+            // otherwise break out of the `href`/`data-path` attributes below.
+            let anchor = html_escape(&field.anchor());
+            let name = html_escape(field.name());
+            if field.children.is_empty() {
+                writeln!(
+                    out,
+                    "<li><a href=\"#{anchor}\" data-path=\"{anchor}\">{name}</a></li>",
+                )
+                .map_err(io_err)?;
+            } else {
+                writeln!(out, "<li><details open><summary><a href=\"#{anchor}\" data-path=\"{anchor}\">{name}</a></summary><ul>",
+                ).map_err(io_err)?;
+                write_sidebar(&field.children, out)?;
+                writeln!(out, "</ul></details></li>").map_err(io_err)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the anchored `<section>` per field, recursing into nested records.
+    fn write_sections(
+        fields: &[HtmlField],
+        registry: &HashMap<String, String>,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        for field in fields {
+            // See the matching comment in `write_sidebar`: the anchor is attacker-/author-
+            // controlled (it's derived from field names) and lands in attribute position here too.
+            writeln!(
+                out,
+                "<section id=\"{}\" class=\"field\">",
+                html_escape(&field.anchor())
+            )
+            .map_err(io_err)?;
+            writeln!(out, "<h2><code>{}</code></h2>", html_escape(field.name())).map_err(io_err)?;
+            if let Some(sig) = mk_signature(field, registry) {
+                writeln!(out, "<pre class=\"signature\">{}</pre>", sig).map_err(io_err)?;
+            }
+            if let Some(doc_html) = &field.doc_html {
+                writeln!(out, "{}", doc_html).map_err(io_err)?;
+            }
+            if let Some(first_child) = field.children.first() {
+                writeln!(
+                    out,
+                    "<p class=\"jump\"><a href=\"#{}\">Jump to fields &rarr;</a></p>",
+                    html_escape(&first_child.anchor())
+                )
+                .map_err(io_err)?;
+            }
+            writeln!(out, "</section>").map_err(io_err)?;
+            write_sections(&field.children, registry, out)?;
+        }
+        Ok(())
+    }
+
+    const STYLE: &str = r#"<style>
+body { display: flex; margin: 0; font-family: sans-serif; }
+#sidebar { width: 260px; overflow-y: auto; height: 100vh; position: sticky; top: 0; padding: 1em; box-sizing: border-box; border-right: 1px solid #ddd; }
+#sidebar ul { list-style: none; padding-left: 1em; }
+main { flex: 1; padding: 2em; max-width: 60em; }
+.field { scroll-margin-top: 1em; border-bottom: 1px solid #eee; padding-bottom: 1em; }
+.signature { background: #f6f6f6; padding: 0.5em; }
+</style>"#;
+
+    /// Restores navigation state on deep-linking: when the page loads (or the URL fragment
+    /// changes) with a `#foo.bar.baz` anchor, scroll the matching section into view and expand
+    /// its ancestor `<details>` in the sidebar.
+    const SCROLL_SCRIPT: &str = r#"<script>
+function gotoFragment() {
+  var id = decodeURIComponent(location.hash.slice(1));
+  if (!id) return;
+  var target = document.getElementById(id);
+  if (!target) return;
+  var node = document.querySelector('#sidebar [data-path="' + CSS.escape(id) + '"]');
+  while (node) {
+    if (node.tagName === "DETAILS") { node.open = true; }
+    node = node.parentElement;
+  }
+  target.scrollIntoView();
+}
+window.addEventListener("hashchange", gotoFragment);
+window.addEventListener("DOMContentLoaded", gotoFragment);
+</script>"#;
+
     /// Parses a string into markdown and increases any headers in the markdown by the specified level.
     /// This allows having headers in documentation without clashing with the structure of the document.
     fn parse_documentation<'a>(
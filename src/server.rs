@@ -0,0 +1,410 @@
+//! Language Server Protocol implementation for Nickel.
+//!
+//! This module backs the `nickel-lsp` binary (see `src/bin/nickel-lsp.rs`). A single [`Cache`] is
+//! kept alive for the lifetime of the connection: `textDocument/didOpen` and
+//! `textDocument/didChange` feed the editor's buffer in via [`Cache::add_source`] /
+//! [`Cache::set_input`], and every other request is served by re-demanding the relevant queries
+//! (see [`crate::cache`]'s incremental query engine) rather than re-running the whole pipeline.
+//! This is what lets retyping a single character only re-typecheck the files that actually depend
+//! on the edited one.
+use crate::cache::{Cache, Envs};
+use crate::error::{Error, ToDiagnostic};
+use crate::identifier::Ident;
+use crate::position::TermPos;
+use crate::term::{RichTerm, Term};
+use codespan::{FileId, Files};
+use codespan_reporting::diagnostic::Severity;
+use lsp_server::{Connection, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{DidChangeTextDocument, DidOpenTextDocument, Notification as _, PublishDiagnostics},
+    request::{Request as _, HoverRequest, GotoDefinition},
+    Diagnostic, DiagnosticSeverity, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    Location, MarkupContent, MarkupKind, Position, PublishDiagnosticsParams, Range, Url,
+};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::ffi::OsString;
+
+/// The running state of a `nickel-lsp` connection: one cache shared by every request, plus a map
+/// from open document URIs to the `FileId` they were registered under.
+pub struct Server {
+    connection: Connection,
+    cache: Cache,
+    envs: Envs,
+    documents: HashMap<Url, FileId>,
+}
+
+impl Server {
+    pub fn new(connection: Connection) -> Result<Self, Error> {
+        let mut cache = Cache::new();
+        let envs = cache.prepare_stdlib()?;
+        Ok(Server {
+            connection,
+            cache,
+            envs,
+            documents: HashMap::new(),
+        })
+    }
+
+    /// Run the main request/notification loop until the client asks to shut down.
+    pub fn run(mut self) -> Result<(), Box<dyn StdError + Sync + Send>> {
+        loop {
+            match self.connection.receiver.recv()? {
+                Message::Request(req) => {
+                    if self.connection.handle_shutdown(&req)? {
+                        return Ok(());
+                    }
+                    self.handle_request(req)?;
+                }
+                Message::Notification(not) => self.handle_notification(not)?,
+                // We never send requests of our own, so no responses are expected back.
+                Message::Response(_) => (),
+            }
+        }
+    }
+
+    fn handle_notification(&mut self, not: Notification) -> Result<(), Box<dyn StdError + Sync + Send>> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: lsp_types::DidOpenTextDocumentParams = serde_json::from_value(not.params)?;
+                let uri = params.text_document.uri;
+                let file_id = self
+                    .cache
+                    .add_source(OsString::from(uri.as_str()), params.text_document.text.as_bytes())?;
+                self.documents.insert(uri.clone(), file_id);
+                self.publish_diagnostics(uri, file_id)?;
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: lsp_types::DidChangeTextDocumentParams = serde_json::from_value(not.params)?;
+                let uri = params.text_document.uri;
+                if let Some(&file_id) = self.documents.get(&uri) {
+                    // We only ever ask the client for full-document sync, so the last change
+                    // event carries the entire new buffer.
+                    if let Some(change) = params.content_changes.into_iter().last() {
+                        self.cache.set_input(file_id, change.text);
+                        self.publish_diagnostics(uri, file_id)?;
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn handle_request(&mut self, req: Request) -> Result<(), Box<dyn StdError + Sync + Send>> {
+        match req.method.as_str() {
+            HoverRequest::METHOD => {
+                let params: HoverParams = serde_json::from_value(req.params)?;
+                let result = self.hover(params)?;
+                self.respond(req.id, result)?;
+            }
+            GotoDefinition::METHOD => {
+                let params: lsp_types::GotoDefinitionParams = serde_json::from_value(req.params)?;
+                let result = self.goto_definition(params)?;
+                self.respond(req.id, result)?;
+            }
+            _ => self.respond(req.id, serde_json::Value::Null)?,
+        }
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        id: RequestId,
+        result: impl serde::Serialize,
+    ) -> Result<(), Box<dyn StdError + Sync + Send>> {
+        self.connection.sender.send(Message::Response(Response::new_ok(id, result)))?;
+        Ok(())
+    }
+
+    /// Parse and typecheck `file_id`, converting any error into LSP diagnostics and publishing
+    /// them (an empty list clears previously reported errors once the document becomes valid).
+    fn publish_diagnostics(
+        &mut self,
+        uri: Url,
+        file_id: FileId,
+    ) -> Result<(), Box<dyn StdError + Sync + Send>> {
+        let diagnostics = match self.cache.typecheck(file_id, &self.envs.type_env) {
+            Ok(()) => Vec::new(),
+            Err(cache_err) => {
+                let error = cache_err.unwrap_error("Server::publish_diagnostics(): expected source to be parsed");
+                to_lsp_diagnostics(&error, self.cache.files_mut())
+            }
+        };
+
+        let params = PublishDiagnosticsParams { uri, diagnostics, version: None };
+        let not = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+        self.connection.sender.send(Message::Notification(not))?;
+        Ok(())
+    }
+
+    /// Show the type annotation and documentation of the record field under the cursor: the
+    /// cursor is mapped to a byte offset, then [`path_at`] walks the record tree to find the
+    /// dotted path of the innermost field whose span contains it, and that field is read from the
+    /// transformed term. Nothing is evaluated: the field's value is not shown, the type shown is
+    /// the field's annotation only (no inferred type), and a field without metadata gets no hover.
+    fn hover(&mut self, params: HoverParams) -> Result<Option<Hover>, Box<dyn StdError + Sync + Send>> {
+        let position = params.text_document_position_params.position;
+        let uri = params.text_document_position_params.text_document.uri;
+        let file_id = match self.documents.get(&uri) {
+            Some(id) => *id,
+            None => return Ok(None),
+        };
+
+        self.cache
+            .typecheck(file_id, &self.envs.type_env)
+            .map_err(|cache_err| cache_err.unwrap_error("Server::hover(): expected source to be parsed"))?;
+        self.cache.transform(file_id)?;
+
+        let term = match self.cache.get(file_id) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let offset = match byte_offset(self.cache.files_mut(), file_id, position) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let mut path = Vec::new();
+        path_at(&term, offset, &mut path);
+
+        let field = match resolve_path(&term, &path) {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+
+        let contents = match field.term.as_ref() {
+            Term::MetaValue(meta) => {
+                let mut md = String::new();
+                if let Some(ty) = &meta.types {
+                    md.push_str(&format!("```nickel\n{}\n```\n", ty));
+                }
+                if let Some(doc) = &meta.doc {
+                    md.push_str(doc);
+                }
+                md
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value: contents }),
+            range: None,
+        }))
+    }
+
+    /// Resolve the `import` under the cursor, if any, to the location of the imported file.
+    /// Reuses [`path_at`]/[`resolve_path`] (see [`Server::hover`]) to find the record field the
+    /// cursor is in, then checks whether that field's (resolved) value is itself an import.
+    fn goto_definition(
+        &mut self,
+        params: lsp_types::GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>, Box<dyn StdError + Sync + Send>> {
+        let position = params.text_document_position_params.position;
+        let uri = params.text_document_position_params.text_document.uri;
+        let file_id = match self.documents.get(&uri) {
+            Some(id) => *id,
+            None => return Ok(None),
+        };
+
+        self.cache
+            .resolve_imports(file_id)
+            .map_err(|cache_err| cache_err.unwrap_error("Server::goto_definition(): expected source to be parsed"))?;
+
+        let term = match self.cache.get(file_id) {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let offset = match byte_offset(self.cache.files_mut(), file_id, position) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        let mut path = Vec::new();
+        path_at(&term, offset, &mut path);
+
+        let field = match resolve_path(&term, &path) {
+            Some(field) => field,
+            None => return Ok(None),
+        };
+
+        let imported_id = match import_target(field) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let target_uri = match self.uri_of(imported_id) {
+            Some(uri) => uri,
+            None => return Ok(None),
+        };
+
+        let range = Range::new(Position::new(0, 0), Position::new(0, 0));
+        Ok(Some(GotoDefinitionResponse::Scalar(Location::new(target_uri, range))))
+    }
+
+    /// The LSP `Url` a file was last reached under: the URI of its open document if the client
+    /// has it open, otherwise derived from the path or URL [`Cache::resolve_import`] registered it
+    /// under.
+    fn uri_of(&self, file_id: FileId) -> Option<Url> {
+        if let Some(uri) = self.documents.iter().find(|(_, &id)| id == file_id).map(|(uri, _)| uri) {
+            return Some(uri.clone());
+        }
+
+        let path = self.cache.path_of(file_id)?;
+        let path = path.to_str()?;
+        Url::parse(path).ok().or_else(|| Url::from_file_path(path).ok())
+    }
+}
+
+/// If `term` (looking through any wrapping [`Term::MetaValue`]) is a resolved `import`, the
+/// `FileId` it resolved to.
+fn import_target(term: &RichTerm) -> Option<FileId> {
+    match term.term.as_ref() {
+        Term::MetaValue(meta) => meta.value.as_ref().and_then(import_target),
+        Term::ResolvedImport(file_id) => Some(*file_id),
+        _ => None,
+    }
+}
+
+/// Map an LSP cursor position back to a byte offset in `file_id`, the inverse of the
+/// line/column lookup [`to_lsp_diagnostics`] does in the other direction. LSP positions count
+/// `character` in UTF-16 code units (see the LSP spec's definition of `Position`), not bytes, so
+/// this walks the line's text converting through [`utf16_offset_to_byte_offset`] rather than
+/// adding `character` to the line's start byte directly.
+fn byte_offset(files: &mut Files<String>, file_id: FileId, position: Position) -> Option<usize> {
+    let line_range = files.line_range(file_id, position.line as usize).ok()?;
+    let line = &files.source(file_id)[line_range.clone()];
+    let offset_in_line = utf16_offset_to_byte_offset(line, position.character as usize)?;
+    Some(line_range.start + offset_in_line)
+}
+
+/// Convert a UTF-16 code unit offset within `line` to a byte offset, the inverse of
+/// [`byte_offset_to_utf16`]. Returns `None` if `utf16_offset` falls strictly inside a surrogate
+/// pair or past the end of the line.
+fn utf16_offset_to_byte_offset(line: &str, utf16_offset: usize) -> Option<usize> {
+    let mut utf16_count = 0;
+    for (byte_idx, ch) in line.char_indices() {
+        if utf16_count == utf16_offset {
+            return Some(byte_idx);
+        }
+        utf16_count += ch.len_utf16();
+    }
+    if utf16_count == utf16_offset {
+        Some(line.len())
+    } else {
+        None
+    }
+}
+
+/// Convert a byte offset within `line` to the UTF-16 code unit offset LSP expects, the inverse of
+/// [`utf16_offset_to_byte_offset`].
+fn byte_offset_to_utf16(line: &str, byte_offset: usize) -> usize {
+    line[..byte_offset.min(line.len())]
+        .chars()
+        .map(char::len_utf16)
+        .sum()
+}
+
+/// Map a byte offset in `file_id` back to an LSP `Position`, converting the in-line byte offset to
+/// UTF-16 code units via [`byte_offset_to_utf16`].
+fn lsp_position(files: &mut Files<String>, file_id: FileId, byte_offset: usize) -> Position {
+    let line_index = files.line_index(file_id, byte_offset as u32);
+    let line_range = files
+        .line_range(file_id, line_index.to_usize())
+        .expect("line_index always returns a valid line for this file");
+    let line = &files.source(file_id)[line_range.clone()];
+    let column = byte_offset_to_utf16(line, byte_offset.saturating_sub(line_range.start));
+    Position::new(line_index.to_usize() as u32, column as u32)
+}
+
+/// Does `term`'s span contain `offset`?
+fn span_contains(term: &RichTerm, offset: usize) -> bool {
+    match term.pos {
+        TermPos::Original(span) | TermPos::Inherited(span) => {
+            (span.start.to_usize()..span.end.to_usize()).contains(&offset)
+        }
+        TermPos::None => false,
+    }
+}
+
+/// Walk down `term`'s record fields, appending to `path` the name of each field whose span
+/// contains `offset`, outermost first, down to the innermost such field. Stops as soon as no field's span contains it, e.g. once it reaches a
+/// non-record value or a field with no recorded position.
+fn path_at(term: &RichTerm, offset: usize, path: &mut Vec<String>) {
+    let record_term = match term.term.as_ref() {
+        Term::MetaValue(meta) => match &meta.value {
+            Some(value) => value,
+            None => return,
+        },
+        _ => term,
+    };
+
+    let map = match record_term.term.as_ref() {
+        Term::Record(map, _) | Term::RecRecord(map, _, _, _) => map,
+        _ => return,
+    };
+
+    for (ident, field) in map.iter() {
+        if span_contains(field, offset) {
+            path.push(ident.label.clone());
+            path_at(field, offset, path);
+            return;
+        }
+    }
+}
+
+/// Look up the field at `path` (as built by [`path_at`]) inside `term`, descending through
+/// `MetaValue`s into their record value at each step.
+fn resolve_path<'a>(term: &'a RichTerm, path: &[String]) -> Option<&'a RichTerm> {
+    let (name, rest) = match path.split_first() {
+        Some(pair) => pair,
+        None => return Some(term),
+    };
+
+    let record_term = match term.term.as_ref() {
+        Term::MetaValue(meta) => meta.value.as_ref()?,
+        _ => term,
+    };
+
+    let map = match record_term.term.as_ref() {
+        Term::Record(map, _) | Term::RecRecord(map, _, _, _) => map,
+        _ => return None,
+    };
+
+    let field = map.get(&Ident::from(name.as_str()))?;
+    resolve_path(field, rest)
+}
+
+/// Convert a Nickel [`Error`] into the LSP diagnostics the client expects, mapping each
+/// `codespan` span to an LSP [`Range`] via the shared file database. Positions are computed
+/// through [`lsp_position`] rather than `Files::location`'s `Column`, since LSP counts `character`
+/// in UTF-16 code units and a line's byte-based column would mislocate any non-ASCII line.
+fn to_lsp_diagnostics(error: &Error, files: &mut Files<String>) -> Vec<Diagnostic> {
+    let contracts_id = None;
+    error
+        .to_diagnostic(files, contracts_id)
+        .into_iter()
+        .map(|d| Diagnostic {
+            range: d
+                .labels
+                .first()
+                .map(|label| {
+                    Range::new(
+                        lsp_position(files, label.file_id, label.range.start),
+                        lsp_position(files, label.file_id, label.range.end),
+                    )
+                })
+                .unwrap_or_else(|| Range::new(Position::new(0, 0), Position::new(0, 0))),
+            severity: Some(match d.severity {
+                Severity::Error | Severity::Bug => DiagnosticSeverity::ERROR,
+                Severity::Warning => DiagnosticSeverity::WARNING,
+                Severity::Note => DiagnosticSeverity::INFORMATION,
+                Severity::Help => DiagnosticSeverity::HINT,
+            }),
+            message: d.message,
+            ..Default::default()
+        })
+        .collect()
+}